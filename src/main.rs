@@ -2,6 +2,8 @@ use clap::{App, Arg};
 use serde::{Deserialize, Serialize};
 use tls_parser::{parse_tls_extensions, parse_tls_plaintext};
 use tokio::io;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::select;
@@ -12,58 +14,303 @@ use dashmap::{DashMap};
 use log::{info, warn, debug, error};
 use stderrlog;
 use rand::distributions::{Alphanumeric, DistString};
+use tokio_socks::tcp::Socks5Stream;
+
+mod proxy_protocol;
+mod quic;
+mod reload;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Socks5Config {
+    addr: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+}
+
+/// Which transport an upstream is reached over. `Udp` upstreams are only
+/// served by the QUIC/UDP listener: a TCP connection whose SNI resolves to
+/// one is refused, without falling back to `DEFAULT`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scheme {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct Upstream {
+    pub(crate) addr: String,
+    /// Prepend a PROXY protocol header to this upstream's connection so it
+    /// can see the real client source address.
+    #[serde(default)]
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Dial this upstream through a SOCKS5 proxy instead of connecting to it
+    /// directly, e.g. to reach services only available over Tor.
+    #[serde(default)]
+    socks5: Option<Socks5Config>,
+    /// Whether this upstream is reached over TCP (TLS) or UDP (QUIC).
+    #[serde(default)]
+    pub(crate) scheme: Scheme,
+}
+
+/// Connect to `upstream_addr`, optionally tunnelling the connection through
+/// a SOCKS5 proxy.
+async fn connect_upstream(upstream_addr: &str, socks5: Option<&Socks5Config>) -> io::Result<TcpStream> {
+    match socks5 {
+        Some(proxy) => {
+            let stream = match (&proxy.username, &proxy.password) {
+                (Some(user), Some(pass)) => {
+                    Socks5Stream::connect_with_password(proxy.addr.as_str(), upstream_addr, user.as_str(), pass.as_str())
+                        .await
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                }
+                _ => Socks5Stream::connect(proxy.addr.as_str(), upstream_addr)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+            };
+            Ok(stream.into_inner())
+        }
+        None => TcpStream::connect(upstream_addr).await,
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug)]
-struct Config {
-    bind: String,
-    upstream: DashMap<String, String>,
+pub(crate) struct Config {
+    pub(crate) bind: Vec<String>,
+    pub(crate) upstream: DashMap<String, Upstream>,
 }
 
+/// Expand a single `bind` entry into the concrete address(es) to listen on.
+///
+/// A bare port (`"8443"`, `":8443"`) or an explicitly unspecified IPv4
+/// address (`"0.0.0.0:8443"`) is expanded to a single `[::]:8443` listener:
+/// on Linux (and most other OSes) with the default `bindv6only=0`, a socket
+/// bound to `[::]` already accepts IPv4 connections, so this serves
+/// dual-stack by default without the `EADDRINUSE` that binding both
+/// `0.0.0.0` and `[::]` on the same port would hit.
+fn expand_bind_addr(addr: &str) -> Vec<String> {
+    if let Ok(port) = addr.trim_start_matches(':').parse::<u16>() {
+        return vec![format!("[::]:{}", port)];
+    }
+    if let Ok(sock_addr) = addr.parse::<std::net::SocketAddr>() {
+        if sock_addr.ip().is_unspecified() {
+            return vec![format!("[::]:{}", sock_addr.port())];
+        }
+    }
+    vec![addr.to_string()]
+}
 
-fn get_sni_from_packet(packet: &[u8]) -> Option<String> {
-    let res: Result<(&[u8], tls_parser::TlsPlaintext), tls_parser::Err<tls_parser::nom::error::Error<&[u8]>>> = parse_tls_plaintext(&packet);
-    if res.is_err() {
-        return None;
+/// Bind a single listener and accept connections on it, handing each one to
+/// `handle_client`. Returns the error if binding or any accept fails.
+async fn run_listener(addr: String, upstreams: Arc<DashMap<String, Upstream>>) -> io::Result<()> {
+    let listener: TcpListener = TcpListener::bind(&addr).await?;
+    info!("Listening on {}", addr);
+    loop {
+        let upstreams: Arc<DashMap<String, Upstream>> = upstreams.clone();
+        let (client, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            handle_client(client, upstreams).await;
+        });
     }
-    let tls_message: &tls_parser::TlsMessage = &res.unwrap().1.msg[0];
+}
+
+
+/// Walk a ClientHello's extensions once, pulling out both the SNI hostname
+/// and the offered ALPN protocol list (in the client's preference order).
+///
+/// `packet` is fully attacker-controlled (it reaches here straight off the
+/// wire, before any upstream is chosen, from both the TCP and QUIC/UDP
+/// listeners), so every step here returns `None`/skips on malformed input
+/// instead of unwrapping or panicking.
+pub(crate) fn get_sni_alpn_from_packet(packet: &[u8]) -> (Option<String>, Vec<String>) {
+    let (_, plaintext) = match parse_tls_plaintext(&packet) {
+        Ok(r) => r,
+        Err(_) => return (None, Vec::new()),
+    };
+    let tls_message = match plaintext.msg.first() {
+        Some(m) => m,
+        None => return (None, Vec::new()),
+    };
     if let tls_parser::TlsMessage::Handshake(handshake) = tls_message {
         if let tls_parser::TlsMessageHandshake::ClientHello(client_hello) = handshake {
-            // get the extensions
-            let extensions: &[u8] = client_hello.ext.unwrap();
-            // parse the extensions
-            let res: Result<(&[u8], Vec<tls_parser::TlsExtension>), tls_parser::Err<tls_parser::nom::error::Error<&[u8]>>> = parse_tls_extensions(extensions);
-            // iterate over the extensions and find the SNI
-            for extension in res.unwrap().1 {
-                if let tls_parser::TlsExtension::SNI(sni) = extension {
-                    // get the hostname
-                    let hostname: &[u8] = sni[0].1;
-                    let s: String = match String::from_utf8(hostname.to_vec()) {
-                        Ok(v) => v,
-                        Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-                    };
-                    return Some(s);
+            let extensions: &[u8] = match client_hello.ext {
+                Some(ext) => ext,
+                None => return (None, Vec::new()),
+            };
+            let extensions = match parse_tls_extensions(extensions) {
+                Ok((_, extensions)) => extensions,
+                Err(_) => return (None, Vec::new()),
+            };
+            let mut sni: Option<String> = None;
+            let mut alpn: Vec<String> = Vec::new();
+            // iterate over the extensions and find the SNI and ALPN
+            for extension in extensions {
+                match extension {
+                    tls_parser::TlsExtension::SNI(sni_list) => {
+                        // get the hostname; SNI isn't guaranteed to be valid UTF-8,
+                        // so fall back to no SNI rather than reject the whole packet
+                        if let Some(hostname) = sni_list.first().map(|(_, name)| *name) {
+                            sni = String::from_utf8(hostname.to_vec()).ok();
+                        }
+                    }
+                    tls_parser::TlsExtension::ALPN(protocols) => {
+                        alpn = protocols
+                            .into_iter()
+                            .filter_map(|p| String::from_utf8(p.to_vec()).ok())
+                            .collect();
+                    }
+                    _ => {}
                 }
             }
+            return (sni, alpn);
         }
     }
-    None
+    (None, Vec::new())
 }
 
-async fn handle_client(client: TcpStream, up: Arc<DashMap<String,String>>) {
+/// ClientHellos larger than this (extensions, ECH, post-quantum key shares...)
+/// are rejected rather than buffered indefinitely.
+pub(crate) const MAX_CLIENT_HELLO_BYTES: usize = 16 * 1024;
+
+/// Read more bytes from `client` into `buf`, bailing once `buf` would exceed
+/// `MAX_CLIENT_HELLO_BYTES` or the connection closes.
+async fn fill_client_hello_buf(client: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<()> {
+    if buf.len() >= MAX_CLIENT_HELLO_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "ClientHello exceeded maximum buffered size",
+        ));
+    }
+    let mut chunk = [0u8; 4096];
+    let want = (MAX_CLIENT_HELLO_BYTES - buf.len()).min(chunk.len());
+    let n = client.read(&mut chunk[..want]).await?;
+    if n == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed while reading ClientHello",
+        ));
+    }
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+/// Read the ClientHello from `client`, reassembling it across however many
+/// TLS records and TCP segments it takes.
+///
+/// Unlike a single fixed-size `peek`, this reads incrementally: each TLS
+/// record is buffered in full per its declared length, and records keep
+/// being consumed until the handshake message's own 4-byte header reports
+/// the ClientHello is complete (it can legitimately span several records
+/// when padded with large extensions like ECH or post-quantum key shares).
+///
+/// Returns `(raw, record)`: `raw` is every byte read from the socket,
+/// verbatim, for the caller to replay to the upstream (bytes pipelined
+/// right after the ClientHello, e.g. TLS 1.3 early data, are never
+/// dropped); `record` is the ClientHello repacked into a single TLS
+/// plaintext record for `get_sni_alpn_from_packet` to parse.
+async fn read_client_hello(client: &mut TcpStream) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut handshake = Vec::new();
+    let mut consumed = 0usize;
+    loop {
+        while buf.len() < consumed + 5 {
+            fill_client_hello_buf(client, &mut buf).await?;
+        }
+        if buf[consumed] != 0x16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a TLS handshake record",
+            ));
+        }
+        let record_len = u16::from_be_bytes([buf[consumed + 3], buf[consumed + 4]]) as usize;
+        let record_end = consumed + 5 + record_len;
+        while buf.len() < record_end {
+            fill_client_hello_buf(client, &mut buf).await?;
+        }
+        handshake.extend_from_slice(&buf[consumed + 5..record_end]);
+        consumed = record_end;
+
+        if handshake.len() >= 4 {
+            let hs_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + hs_len {
+                let mut record = Vec::with_capacity(5 + 4 + hs_len);
+                record.push(0x16);
+                record.extend_from_slice(&buf[1..3]); // legacy record version, from the first record
+                record.extend_from_slice(&((4 + hs_len) as u16).to_be_bytes());
+                record.extend_from_slice(&handshake[..4 + hs_len]);
+                return Ok((buf, record));
+            }
+        }
+    }
+}
+
+/// Look up `sni` in `up`: an exact match wins outright; otherwise the most
+/// specific wildcard pattern (`*.example.com`) present in the table is used,
+/// so `*.a.example.com` beats `*.example.com` for `foo.a.example.com`.
+pub(crate) fn match_upstream<'a>(
+    up: &'a DashMap<String, Upstream>,
+    sni: &str,
+) -> Option<dashmap::mapref::one::Ref<'a, String, Upstream>> {
+    if let Some(exact) = up.get(sni) {
+        return Some(exact);
+    }
+    let mut best_suffix: Option<String> = None;
+    for entry in up.iter() {
+        let pattern = entry.key();
+        if let Some(suffix) = pattern.strip_prefix("*.") {
+            if sni.ends_with(&format!(".{}", suffix))
+                && best_suffix.as_ref().map_or(true, |best| suffix.len() > best.len())
+            {
+                best_suffix = Some(suffix.to_string());
+            }
+        }
+    }
+    best_suffix.and_then(|suffix| up.get(&format!("*.{}", suffix)))
+}
+
+/// Key used to route by SNI qualified with a negotiated ALPN protocol, e.g.
+/// `example.com|h2` routed separately from plain `example.com`.
+fn alpn_key(sni: &str, alpn: &str) -> String {
+    format!("{}|{}", sni, alpn)
+}
+
+async fn handle_client(mut client: TcpStream, up: Arc<DashMap<String,Upstream>>) {
     let ray_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
     let src_addr = client.peer_addr().unwrap();
+    let dst_addr = client.local_addr().unwrap();
     let metadata = format!("ray_id={} src_ip={}", ray_id, src_addr);
 
-    let mut buf = [0; 1024];
-    client.peek(&mut buf).await.expect("peek failed");
-    let sni: Option<String> = get_sni_from_packet(&buf);
+    let (hello_buf, hello_record): (Vec<u8>, Vec<u8>) = match read_client_hello(&mut client).await {
+        Ok(b) => b,
+        Err(e) => {
+            info!("{} Failed to read ClientHello: {}", metadata, e);
+            return;
+        }
+    };
+    let (sni, alpn_protocols) = get_sni_alpn_from_packet(&hello_record);
     if sni.is_none() {
         info!("{}: No SNI found", metadata);
         return;
     } else {
         let sni_string: String = sni.unwrap().to_string();
         info!("{} SNI: {}",metadata, &sni_string);
-        let mut upstream: Option<dashmap::mapref::one::Ref<String, String>> = up.get(&sni_string);
+        // try the (sni, alpn) composite key first, in the client's preference order
+        let mut upstream: Option<dashmap::mapref::one::Ref<String, Upstream>> = alpn_protocols
+            .iter()
+            .find_map(|proto| up.get(&alpn_key(&sni_string, proto)));
+        if upstream.is_none() {
+            upstream = match_upstream(&up, &sni_string);
+        }
         if upstream.is_none() {
             // check DEFAULT upstream
             upstream = up.get("DEFAULT");
@@ -72,8 +319,16 @@ async fn handle_client(client: TcpStream, up: Arc<DashMap<String,String>>) {
                 return;
             }
         }
-        let upstream_addr = upstream.unwrap().to_string();
-        let server: Result<TcpStream, io::Error> = TcpStream::connect(upstream_addr.clone()).await;
+        let upstream = upstream.unwrap();
+        if upstream.scheme == Scheme::Udp {
+            info!("{} SNI {} resolved to a UDP upstream, refusing on the TCP listener", metadata, &sni_string);
+            return;
+        }
+        let upstream_addr = upstream.addr.clone();
+        let proxy_protocol = upstream.proxy_protocol;
+        let socks5 = upstream.socks5.clone();
+        drop(upstream);
+        let server: Result<TcpStream, io::Error> = connect_upstream(&upstream_addr, socks5.as_ref()).await;
         if server.is_err() {
             warn!("{} Failed to connect to upstream: {}",metadata, upstream_addr);
             return;
@@ -82,6 +337,19 @@ async fn handle_client(client: TcpStream, up: Arc<DashMap<String,String>>) {
         let (mut eread, mut ewrite) = client.into_split();
         let (mut oread, mut owrite) = server.into_split();
         info!("{} Connected to upstream: {}",metadata,upstream_addr);
+        if let Some(version) = proxy_protocol {
+            let header = proxy_protocol::build_header(version, src_addr, dst_addr);
+            if let Err(e) = owrite.write_all(&header).await {
+                warn!("{} Failed to write PROXY protocol header: {}", metadata, e);
+                return;
+            }
+        }
+        // Replay the ClientHello bytes we consumed while hunting for the SNI;
+        // they can no longer be left in the socket for the upstream to read.
+        if let Err(e) = owrite.write_all(&hello_buf).await {
+            warn!("{} Failed to replay ClientHello to upstream: {}", metadata, e);
+            return;
+        }
         let e2o: tokio::task::JoinHandle<Result<u64, io::Error>> = tokio::spawn(async move { io::copy(&mut eread, &mut owrite).await });
         let o2e: tokio::task::JoinHandle<Result<u64, io::Error>> = tokio::spawn(async move { io::copy(&mut oread, &mut ewrite).await });
         select! {
@@ -128,18 +396,27 @@ async fn main() -> std::io::Result<()> {
         .unwrap();
 
 
-    let listener: TcpListener = TcpListener::bind(c.bind.clone()).await?;
-    info!("Listening on {}", c.bind);
-    let mut handles: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    let upstreams: Arc<DashMap<String, Upstream>> = Arc::new(c.upstream);
 
-    let upstreams: Arc<DashMap<String, String>> =Arc::new(c.upstream); 
-    loop {
-        let upstreams: Arc<DashMap<String, String>> = upstreams.clone();
-        let (client, _) = listener.accept().await?;
-        let handle: tokio::task::JoinHandle<()> = tokio::spawn(async move {
-            handle_client(client, upstreams).await;
-        });
-        handles.push(handle);
+    let reload_upstreams: Arc<DashMap<String, Upstream>> = upstreams.clone();
+    let config_path: String = config.to_string();
+    tokio::spawn(async move { reload::watch_config(config_path, reload_upstreams).await });
+
+    let bind_addrs: Vec<String> = c.bind.iter().flat_map(|b| expand_bind_addr(b)).collect();
+    let mut handles: Vec<tokio::task::JoinHandle<io::Result<()>>> = Vec::new();
+    for addr in bind_addrs {
+        let tcp_addr = addr.clone();
+        let tcp_upstreams: Arc<DashMap<String, Upstream>> = upstreams.clone();
+        handles.push(tokio::spawn(async move { run_listener(tcp_addr, tcp_upstreams).await }));
+        let udp_upstreams: Arc<DashMap<String, Upstream>> = upstreams.clone();
+        handles.push(tokio::spawn(async move { quic::run_udp_listener(addr, udp_upstreams).await }));
+    }
+
+    for handle in handles {
+        if let Err(e) = handle.await.expect("listener task panicked") {
+            error!("listener exited: {}", e);
+        }
     }
+    Ok(())
 }
 
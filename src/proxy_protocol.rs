@@ -0,0 +1,67 @@
+use std::net::SocketAddr;
+
+use crate::ProxyProtocolVersion;
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// Build a PROXY protocol header (v1 or v2) describing a connection from
+/// `src` to `dst`, to be written to the upstream before splicing begins.
+pub fn build_header(version: ProxyProtocolVersion, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    // Listeners bind the unspecified dual-stack [::], so an IPv4 client's
+    // peer/local addr arrives as an IPv4-mapped V6 address (::ffff:a.b.c.d).
+    // Unmap both ends up front so the header reflects the real family.
+    let src = SocketAddr::new(src.ip().to_canonical(), src.port());
+    let dst = SocketAddr::new(dst.ip().to_canonical(), dst.port());
+    match version {
+        ProxyProtocolVersion::V1 => build_v1(src, dst),
+        ProxyProtocolVersion::V2 => build_v2(src, dst),
+    }
+}
+
+fn build_v1(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let proto = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        proto,
+        src.ip(),
+        dst.ip(),
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
+
+fn build_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(28);
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(0x21); // version 2, PROXY command
+
+    let mut addresses = Vec::with_capacity(12);
+    match (src, dst) {
+        (SocketAddr::V4(s), SocketAddr::V4(d)) => {
+            header.push(0x11); // AF_INET + STREAM
+            addresses.extend_from_slice(&s.ip().octets());
+            addresses.extend_from_slice(&d.ip().octets());
+            addresses.extend_from_slice(&s.port().to_be_bytes());
+            addresses.extend_from_slice(&d.port().to_be_bytes());
+        }
+        (SocketAddr::V6(s), SocketAddr::V6(d)) => {
+            header.push(0x21); // AF_INET6 + STREAM
+            addresses.extend_from_slice(&s.ip().octets());
+            addresses.extend_from_slice(&d.ip().octets());
+            addresses.extend_from_slice(&s.port().to_be_bytes());
+            addresses.extend_from_slice(&d.port().to_be_bytes());
+        }
+        _ => {
+            // mixed address families: emit an UNSPEC header with no address block
+            header.push(0x00);
+            header.extend_from_slice(&0u16.to_be_bytes());
+            return header;
+        }
+    }
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
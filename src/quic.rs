@@ -0,0 +1,389 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes128Gcm, KeyInit, Nonce};
+use dashmap::DashMap;
+use hkdf::Hkdf;
+use log::{debug, info, warn};
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+
+use crate::{get_sni_alpn_from_packet, match_upstream, Scheme, Upstream};
+
+/// Initial salt for QUICv1 (RFC 9001 section 5.2), used to derive the
+/// client's Initial packet protection keys from the Destination Connection
+/// ID on the first Initial packet of a connection.
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x54, 0x33, 0xd5, 0x19, 0x3d, 0x09, 0x7e, 0x44, 0x59, 0x71, 0x87,
+    0xc7, 0xa7, 0xf5, 0x17,
+];
+
+const MAX_DATAGRAM: usize = 1500;
+/// Long-header first byte for an Initial packet in QUICv1 (form=1, fixed=1,
+/// type bits `00`); the lower nibble (reserved bits + packet number length)
+/// is still header-protected at this point so it's masked out of the check.
+const LONG_HEADER_INITIAL_MASK: u8 = 0xf0;
+const LONG_HEADER_INITIAL_VALUE: u8 = 0xc0;
+
+struct InitialSecrets {
+    key: [u8; 16],
+    iv: [u8; 12],
+    hp: [u8; 16],
+}
+
+fn hkdf_expand_label(prk: &Hkdf<Sha256>, label: &str, len: usize) -> Option<Vec<u8>> {
+    let full_label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(3 + full_label.len());
+    info.extend_from_slice(&(len as u16).to_be_bytes());
+    info.push(full_label.len() as u8);
+    info.extend_from_slice(full_label.as_bytes());
+    info.push(0);
+    let mut out = vec![0u8; len];
+    prk.expand(&info, &mut out).ok()?;
+    Some(out)
+}
+
+/// Derive the client-side Initial packet protection keys for a given
+/// Destination Connection ID, per RFC 9001 section 5.2.
+fn derive_client_initial_secrets(dcid: &[u8]) -> Option<InitialSecrets> {
+    let initial_secret = Hkdf::<Sha256>::new(Some(&INITIAL_SALT_V1), dcid);
+    let client_secret = hkdf_expand_label(&initial_secret, "client in", 32)?;
+    let client_hk = Hkdf::<Sha256>::from_prk(&client_secret).ok()?;
+
+    let key = hkdf_expand_label(&client_hk, "quic key", 16)?;
+    let iv = hkdf_expand_label(&client_hk, "quic iv", 12)?;
+    let hp = hkdf_expand_label(&client_hk, "quic hp", 16)?;
+
+    let mut secrets = InitialSecrets { key: [0; 16], iv: [0; 12], hp: [0; 16] };
+    secrets.key.copy_from_slice(&key);
+    secrets.iv.copy_from_slice(&iv);
+    secrets.hp.copy_from_slice(&hp);
+    Some(secrets)
+}
+
+/// Read a QUIC variable-length integer, returning its value and the number
+/// of bytes it occupied.
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1usize << (first >> 6);
+    let bytes = buf.get(..len)?;
+    let mut value = (first & 0x3f) as u64;
+    for b in &bytes[1..] {
+        value = (value << 8) | (*b as u64);
+    }
+    Some((value, len))
+}
+
+/// Compute the AES-ECB header protection mask (RFC 9001 section 5.4.3) from
+/// a 16-byte sample of the packet's protected payload.
+fn header_protection_mask(hp_key: &[u8; 16], sample: &[u8]) -> Option<[u8; 5]> {
+    use aes::cipher::{BlockEncrypt, KeyInit as _};
+    let cipher = aes::Aes128::new_from_slice(hp_key).ok()?;
+    let mut block = aes::Block::clone_from_slice(sample);
+    cipher.encrypt_block(&mut block);
+    let mut mask = [0u8; 5];
+    mask.copy_from_slice(&block[..5]);
+    Some(mask)
+}
+
+fn decode_packet_number(bytes: &[u8]) -> u64 {
+    let mut value = 0u64;
+    for b in bytes {
+        value = (value << 8) | (*b as u64);
+    }
+    value
+}
+
+fn build_nonce(iv: &[u8; 12], packet_number: u64) -> [u8; 12] {
+    let mut nonce = *iv;
+    let pn_bytes = packet_number.to_be_bytes();
+    for i in 0..8 {
+        nonce[4 + i] ^= pn_bytes[i];
+    }
+    nonce
+}
+
+/// Merge a decrypted Initial packet's CRYPTO frames into `crypto` at their
+/// declared offsets, stopping at the first non-CRYPTO/PADDING frame. A
+/// single large ClientHello (ECH, post-quantum key shares...) is routinely
+/// split by the client across the CRYPTO frames of several Initial packets,
+/// each at a different offset, so this is called once per packet of a
+/// connection and accumulates into the same buffer across calls.
+fn merge_crypto_frames(payload: &[u8], crypto: &mut Vec<u8>) -> Option<()> {
+    let mut pos = 0;
+    while pos < payload.len() {
+        let frame_type = payload[pos];
+        match frame_type {
+            0x00 => pos += 1, // PADDING
+            0x06 => {
+                pos += 1;
+                let (offset, n) = read_varint(&payload[pos..])?;
+                pos += n;
+                let (length, n) = read_varint(&payload[pos..])?;
+                pos += n;
+                let data = payload.get(pos..pos + length as usize)?;
+                let end = (offset as usize).checked_add(data.len())?;
+                if end > crate::MAX_CLIENT_HELLO_BYTES {
+                    // offset/length are attacker-controlled varints (up to 2^62); a
+                    // huge declared offset would otherwise resize `crypto` to match,
+                    // aborting the process on the allocation. No real ClientHello
+                    // is anywhere near this size.
+                    return None;
+                }
+                if crypto.len() < end {
+                    crypto.resize(end, 0);
+                }
+                crypto[offset as usize..end].copy_from_slice(data);
+                pos += length as usize;
+            }
+            _ => break, // ACK/other frames aren't expected before the ClientHello
+        }
+    }
+    Some(())
+}
+
+/// Check whether the CRYPTO stream accumulated so far (via
+/// [`merge_crypto_frames`]) contains a complete Handshake message, and if
+/// so parse it for the SNI and ALPN. Returns `None` while more Initial
+/// packets are still needed to complete the ClientHello.
+fn try_extract_client_hello(crypto: &[u8]) -> Option<(Option<String>, Vec<String>)> {
+    if crypto.len() < 4 {
+        return None;
+    }
+    let hs_len = u32::from_be_bytes([0, crypto[1], crypto[2], crypto[3]]) as usize;
+    if crypto.len() < 4 + hs_len {
+        return None;
+    }
+
+    // The CRYPTO stream carries a bare Handshake message; wrap it in a TLS
+    // plaintext record header so the existing ClientHello parser can read it.
+    let mut record = Vec::with_capacity(5 + 4 + hs_len);
+    record.push(0x16); // ContentType::Handshake
+    record.extend_from_slice(&[0x03, 0x01]); // legacy record version
+    record.extend_from_slice(&((4 + hs_len) as u16).to_be_bytes());
+    record.extend_from_slice(&crypto[..4 + hs_len]);
+
+    Some(get_sni_alpn_from_packet(&record))
+}
+
+/// Undo header protection and AEAD decryption on a single QUIC Initial
+/// packet, returning its plaintext frame payload. Returns `None` for
+/// anything that isn't a QUICv1 Initial packet we can decrypt.
+fn decrypt_initial_payload(datagram: &[u8]) -> Option<Vec<u8>> {
+    if datagram.first()? & LONG_HEADER_INITIAL_MASK != LONG_HEADER_INITIAL_VALUE {
+        return None;
+    }
+    let version = u32::from_be_bytes(datagram.get(1..5)?.try_into().ok()?);
+    if version != 1 {
+        return None;
+    }
+
+    let mut pos = 5;
+    let dcid_len = *datagram.get(pos)? as usize;
+    pos += 1;
+    let dcid = datagram.get(pos..pos + dcid_len)?;
+    pos += dcid_len;
+
+    let scid_len = *datagram.get(pos)? as usize;
+    pos += 1;
+    datagram.get(pos..pos + scid_len)?;
+    pos += scid_len;
+
+    let (token_len, n) = read_varint(datagram.get(pos..)?)?;
+    pos += n;
+    datagram.get(pos..pos + token_len as usize)?;
+    pos += token_len as usize;
+
+    let (payload_len, n) = read_varint(datagram.get(pos..)?)?;
+    pos += n;
+    let pn_offset = pos;
+
+    let secrets = derive_client_initial_secrets(dcid)?;
+    let sample_offset = pn_offset + 4;
+    let sample = datagram.get(sample_offset..sample_offset + 16)?;
+    let mask = header_protection_mask(&secrets.hp, sample)?;
+
+    let mut first_byte = *datagram.get(0)?;
+    first_byte ^= mask[0] & 0x0f;
+    let pn_len = (first_byte & 0x03) as usize + 1;
+
+    let mut pn_bytes = datagram.get(pn_offset..pn_offset + pn_len)?.to_vec();
+    for (i, b) in pn_bytes.iter_mut().enumerate() {
+        *b ^= mask[1 + i];
+    }
+    let packet_number = decode_packet_number(&pn_bytes);
+
+    let payload_start = pn_offset + pn_len;
+    let payload_end = pn_offset + payload_len as usize;
+    let ciphertext = datagram.get(payload_start..payload_end)?;
+
+    let mut header = datagram.get(..payload_start)?.to_vec();
+    header[0] = first_byte;
+    header[pn_offset..pn_offset + pn_len].copy_from_slice(&pn_bytes);
+
+    let nonce = build_nonce(&secrets.iv, packet_number);
+    let cipher = Aes128Gcm::new(&secrets.key.into());
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad: &header })
+        .ok()
+}
+
+/// Per-client state while a ClientHello is still being reassembled across
+/// several Initial packets: the CRYPTO stream accumulated so far, and every
+/// raw Initial datagram seen, so they can all be relayed to the upstream
+/// once it's chosen (not just the one that completed the ClientHello).
+struct PendingInitial {
+    crypto: Vec<u8>,
+    datagrams: Vec<Vec<u8>>,
+    created: Instant,
+}
+
+impl Default for PendingInitial {
+    fn default() -> Self {
+        PendingInitial {
+            crypto: Vec::new(),
+            datagrams: Vec::new(),
+            created: Instant::now(),
+        }
+    }
+}
+
+/// Initial packets belonging to one connection attempt that we'll hold onto
+/// while waiting for the rest of a multi-packet ClientHello before giving
+/// up and dropping the connection.
+const MAX_PENDING_INITIAL_DATAGRAMS: usize = 8;
+
+/// How long an incomplete reassembly entry is kept before being swept as
+/// abandoned. Without this, a flood of single partial Initial packets from
+/// spoofed source addresses (which never complete and never hit the
+/// `MAX_PENDING_INITIAL_DATAGRAMS` cap) would grow `reassembly` forever.
+const PENDING_INITIAL_TTL: Duration = Duration::from_secs(10);
+
+/// Bind a UDP listener that multiplexes QUIC Initial packets by SNI,
+/// mirroring the TCP path: the ClientHello in a 4-tuple's Initial packet(s),
+/// reassembled across packets where needed, picks the upstream, after which
+/// datagrams are relayed both ways until the relay goes idle.
+pub(crate) async fn run_udp_listener(
+    addr: String,
+    upstreams: Arc<DashMap<String, Upstream>>,
+) -> std::io::Result<()> {
+    let socket = Arc::new(UdpSocket::bind(&addr).await?);
+    info!("Listening (UDP/QUIC) on {}", addr);
+
+    let sessions: Arc<DashMap<SocketAddr, Arc<UdpSocket>>> = Arc::new(DashMap::new());
+    let reassembly: DashMap<SocketAddr, PendingInitial> = DashMap::new();
+    let mut buf = [0u8; MAX_DATAGRAM];
+    loop {
+        let (n, client_addr) = socket.recv_from(&mut buf).await?;
+        let datagram = &buf[..n];
+
+        if let Some(upstream_sock) = sessions.get(&client_addr) {
+            let _ = upstream_sock.send(datagram).await;
+            continue;
+        }
+
+        reassembly.retain(|_, pending| pending.created.elapsed() < PENDING_INITIAL_TTL);
+
+        let mut drop_pending = false;
+        let client_hello = {
+            let mut pending = reassembly
+                .entry(client_addr)
+                .or_insert_with(PendingInitial::default);
+            pending.datagrams.push(datagram.to_vec());
+            if pending.datagrams.len() > MAX_PENDING_INITIAL_DATAGRAMS {
+                drop_pending = true;
+                None
+            } else {
+                match decrypt_initial_payload(datagram) {
+                    Some(plaintext) if merge_crypto_frames(&plaintext, &mut pending.crypto).is_some() => {
+                        try_extract_client_hello(&pending.crypto)
+                    }
+                    Some(_) => {
+                        // malformed/oversized CRYPTO frame offsets; not a ClientHello we can route
+                        drop_pending = true;
+                        None
+                    }
+                    None => None,
+                }
+            }
+        };
+        if drop_pending {
+            warn!(
+                "QUIC: {} sent too many Initial packets or malformed CRYPTO frames without a complete ClientHello, dropping",
+                client_addr
+            );
+            reassembly.remove(&client_addr);
+            continue;
+        }
+        let (sni, _alpn) = match client_hello {
+            Some((Some(sni), alpn)) => (sni, alpn),
+            Some((None, _)) => {
+                info!("QUIC: ClientHello from {} has no SNI", client_addr);
+                reassembly.remove(&client_addr);
+                continue;
+            }
+            None => {
+                debug!(
+                    "QUIC: ClientHello from {} spans multiple Initial packets, waiting for more",
+                    client_addr
+                );
+                continue;
+            }
+        };
+        let pending_datagrams = reassembly
+            .remove(&client_addr)
+            .map(|(_, p)| p.datagrams)
+            .unwrap_or_default();
+
+        let upstream = match match_upstream(&upstreams, &sni).or_else(|| upstreams.get("DEFAULT")) {
+            Some(u) if u.scheme == Scheme::Udp => u,
+            _ => continue,
+        };
+        let upstream_addr = upstream.addr.clone();
+        drop(upstream);
+
+        let upstream_sock = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(s) => Arc::new(s),
+            Err(e) => {
+                warn!("QUIC: failed to bind relay socket for {}: {}", client_addr, e);
+                continue;
+            }
+        };
+        if let Err(e) = upstream_sock.connect(&upstream_addr).await {
+            warn!("QUIC: failed to connect to upstream {}: {}", upstream_addr, e);
+            continue;
+        }
+        let mut relay_failed = false;
+        for initial_datagram in &pending_datagrams {
+            if let Err(e) = upstream_sock.send(initial_datagram).await {
+                warn!("QUIC: failed to relay Initial packet to {}: {}", upstream_addr, e);
+                relay_failed = true;
+                break;
+            }
+        }
+        if relay_failed {
+            continue;
+        }
+        info!("QUIC: {} routed to {} (SNI: {})", client_addr, upstream_addr, sni);
+        sessions.insert(client_addr, upstream_sock.clone());
+
+        let listener_sock = socket.clone();
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DATAGRAM];
+            loop {
+                match tokio::time::timeout(Duration::from_secs(60), upstream_sock.recv(&mut buf)).await {
+                    Ok(Ok(n)) => {
+                        if listener_sock.send_to(&buf[..n], client_addr).await.is_err() {
+                            break;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            sessions.remove(&client_addr);
+        });
+    }
+}
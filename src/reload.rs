@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use log::{info, warn};
+use tokio::select;
+
+use crate::{Config, Upstream};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watch `path` for changes and apply them to the live upstream table in
+/// place: on Unix a SIGHUP triggers an immediate reload, and the file's
+/// mtime is polled as a fallback (and the only mechanism on non-Unix), so
+/// operators can add/remove SNI routes in production without restarting or
+/// dropping in-flight proxied connections.
+pub(crate) async fn watch_config(path: String, upstreams: Arc<DashMap<String, Upstream>>) {
+    let mut last_mtime = mtime(&path);
+
+    #[cfg(unix)]
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to register SIGHUP handler");
+
+    loop {
+        #[cfg(unix)]
+        {
+            select! {
+                _ = sighup.recv() => {
+                    info!("received SIGHUP, reloading config from {}", path);
+                    reload(&path, &upstreams);
+                    last_mtime = mtime(&path);
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => {
+                    let current = mtime(&path);
+                    if current != last_mtime {
+                        info!("detected change to {}, reloading", path);
+                        reload(&path, &upstreams);
+                        last_mtime = current;
+                    }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let current = mtime(&path);
+            if current != last_mtime {
+                info!("detected change to {}, reloading", path);
+                reload(&path, &upstreams);
+                last_mtime = current;
+            }
+        }
+    }
+}
+
+fn mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Re-parse `path` and apply its `upstream` entries to `upstreams` in place:
+/// insert new/changed keys, then remove keys no longer present. Each
+/// individual key is updated atomically (`DashMap` shards its locking per
+/// key), and inserting before retaining means a lookup racing the reload
+/// can only ever see a key too early (once it's inserted) or too late (its
+/// old value, until its turn comes), never missing outright or briefly
+/// pointing at a removed upstream — but the reload as a whole is not one
+/// atomic swap, so a connection arriving mid-reload can still race a
+/// handful of individual key updates.
+fn reload(path: &str, upstreams: &Arc<DashMap<String, Upstream>>) {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("config reload: failed to read {}: {}", path, e);
+            return;
+        }
+    };
+    let new_config: Config = match toml::from_str(&content) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("config reload: failed to parse {}: {}", path, e);
+            return;
+        }
+    };
+
+    let new_keys: HashSet<String> = new_config.upstream.iter().map(|e| e.key().clone()).collect();
+    for (key, value) in new_config.upstream {
+        upstreams.insert(key, value);
+    }
+    upstreams.retain(|k, _| new_keys.contains(k));
+    info!("config reloaded from {}: {} upstream entries", path, upstreams.len());
+}